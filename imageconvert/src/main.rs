@@ -25,18 +25,94 @@ struct Args {
     /// containing 6-bit and 8-bit RGB values used
     #[arg(short, long, default_value_t = false)]
     debug: bool,
+
+    /// When set, --image-path is treated as a 24-bit truecolor image (BMP)
+    /// that should be quantized down to an .R8 index file, rather than an
+    /// .R8 file that should be expanded up to a truecolor BMP.
+    #[arg(short, long, default_value_t = false)]
+    reverse: bool,
+
+    /// Range of palette indices to exclude from nearest-color matching when
+    /// quantizing a truecolor image down to an .R8 (--reverse). Format is
+    /// "start..end", half-open like a Rust range. Defaults to the first 16
+    /// compatibility colors so UI colors aren't clobbered by the match.
+    #[arg(long, default_value = "0..16")]
+    reserve: String,
+
+    /// When set, --image-path is treated as a truecolor image to quantize
+    /// into a custom 128 color .PLT, rather than being converted itself.
+    #[arg(long, default_value_t = false)]
+    make_palette: bool,
+
+    /// Output format for image_to_bitmap: "bmp" (24-bit RGB) or "png"
+    /// (paletted, with a PLTE chunk).
+    #[arg(long, value_enum, default_value_t = OutputFormat::Bmp)]
+    format: OutputFormat,
+
+    /// Width in pixels of --image-path. If omitted, it's inferred from the file length.
+    #[arg(long)]
+    width: Option<u32>,
+
+    /// Height in pixels of --image-path. See --width.
+    #[arg(long)]
+    height: Option<u32>,
+
+    /// Bits per pixel of --image-path. SPACESIM screens are 8bpp.
+    #[arg(long, default_value_t = 8)]
+    bpp: u32,
+
+    /// Export the assembled 256 color palette, in raw 6-bit form, to a text
+    /// file at this path. Each line's index is the full palette slot; since
+    /// --palette-path places a file's entries from slot 128 on, the exported
+    /// file does not load back in unchanged.
+    #[arg(long)]
+    export_palette_text: Option<std::path::PathBuf>,
+
+    /// Strategy for filling palette indices 128..256 when no
+    /// --palette-path is supplied: "green" (default), "grayscale", or "vga".
+    #[arg(long, value_enum, default_value_t = Fallback::Green)]
+    fallback: Fallback,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum OutputFormat {
+    Bmp,
+    Png,
 }
 
 fn main() {
     let args = Args::parse();
 
-    match args.image_path {
-        Some(image) => image_to_bitmap(&image, args.palette_path.as_deref(), args.debug),
-        None => match args.palette_path {
-                Some(palette) => palette_file_to_bitmap(palette.as_path()),
+    match args.image_path.as_deref() {
+        Some(image) if args.make_palette => image_to_palette(image),
+        Some(image) if args.reverse => bitmap_to_image(image, args.palette_path.as_deref(), &args.reserve, args.debug, args.fallback),
+        Some(image) => image_to_bitmap(image, args.palette_path.as_deref(), args.debug, ImageToBitmapOptions {
+            format: args.format,
+            width: args.width,
+            height: args.height,
+            bpp: args.bpp,
+            fallback: args.fallback,
+        }),
+        None => match args.palette_path.as_deref() {
+                Some(palette) => palette_file_to_bitmap(palette),
                 None => {println!("Must provide either a palette or image or both.")},
         }
     };
+
+    if let Some(export_path) = &args.export_palette_text {
+        let palette = assemble_palette(args.palette_path.as_deref(), args.fallback, &[]);
+        export_palette_text(&palette, export_path);
+    }
+}
+
+/// Parse a "start..end" range string as used by --reserve. Panics with a
+/// helpful message on malformed input, matching this tool's habit of
+/// failing loudly via `expect` rather than threading a Result through main.
+fn parse_reserve_range(range: &str) -> std::ops::Range<usize> {
+    let (start, end) = range.split_once("..").expect("--reserve must look like \"start..end\"");
+    let start: usize = start.trim().parse().expect("--reserve start must be a number");
+    let end: usize = end.trim().parse().expect("--reserve end must be a number");
+    start..end
 }
 
 #[derive(Clone)]
@@ -126,32 +202,185 @@ fn overlay_palette(mut palette: Vec<PalettePixel>, overlay: Vec<PalettePixel>, o
     palette
 }
 
+/// Load a .PLT file, auto-detecting raw binary (3 bytes RGB per color) vs.
+/// the human-readable text format. Binary .PLT bytes are valid UTF-8 too,
+/// so we require the whole file to parse as text before trusting that.
 fn load_palette(path: &Path) -> Vec<PalettePixel> {
     let palette_file_bytes = fs::read(path).expect("Could not read palette file");
+
+    let palette_file_colors = std::str::from_utf8(&palette_file_bytes).ok()
+        .and_then(try_parse_text_palette)
+        .unwrap_or_else(|| parse_binary_palette(&palette_file_bytes));
+
+    println!("Found {} colors in palette {}", palette_file_colors.len(), path.display());
+    palette_file_colors
+}
+
+fn parse_binary_palette(palette_file_bytes: &[u8]) -> Vec<PalettePixel> {
     let palette_pixel_zero = PalettePixel { r: 0, g: 0, b: 0 };
     let mut palette_file_colors: Vec<PalettePixel> = vec![palette_pixel_zero; palette_file_bytes.len()/3];
     for (bytes, color) in zip(palette_file_bytes.chunks_exact(3), &mut palette_file_colors){
         let view = dataview::DataView::from_mut(color);
         view.write(0,bytes);
     }
-
-    println!("Found {} colors in palette {}", palette_file_colors.len(), path.display());
     palette_file_colors
 }
 
-fn load_image(path: &Path) -> (Vec<IndexPixel>, Vec<u8>) {
-    let image_file_bytes = fs::read(path).expect("Could not read image file.");
-    let mut image_file_indexes: Vec<IndexPixel> = vec![IndexPixel{raw_index:0}; image_file_bytes.len()];
+/// Parse "index, R, G, B  # name" lines ('#' comments, blank lines ignored).
+/// Returns None on any malformed line, rather than guessing.
+fn try_parse_text_palette(text: &str) -> Option<Vec<PalettePixel>> {
+    let mut entries: Vec<(usize, PalettePixel)> = Vec::new();
+
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        if fields.len() != 4 {
+            return None;
+        }
+
+        let index: usize = fields[0].parse().ok()?;
+        let r: u8 = fields[1].parse().ok()?;
+        let g: u8 = fields[2].parse().ok()?;
+        let b: u8 = fields[3].parse().ok()?;
+        entries.push((index, PalettePixel { r, g, b }));
+    }
+
+    if entries.is_empty() {
+        return None;
+    }
+
+    let size = entries.iter().map(|(index, _)| index + 1).max().unwrap_or(0);
+    let mut palette = const_palette(size, PalettePixel { r: 0, g: 0, b: 0 });
+    for (index, color) in entries {
+        palette[index] = color;
+    }
+    Some(palette)
+}
+
+/// Export a palette to the human-readable text format read by
+/// `try_parse_text_palette`, for hand-editing or inspection.
+fn export_palette_text(palette: &[PalettePixel], path: &Path) {
+    let mut text = String::new();
+    for (index, color) in palette.iter().enumerate() {
+        text.push_str(&format!("{}, {}, {}, {}  # slot {}\n", index, color.r, color.g, color.b, index));
+    }
+
+    println!("Writing out {}", path.display());
+    fs::write(path, text).expect("Could not write text palette file");
+}
+
+fn load_image(path: &Path) -> Vec<u8> {
+    fs::read(path).expect("Could not read image file.")
+}
+
+/// Known SPACESIM asset dimensions, keyed by 8bpp file length in bytes.
+const KNOWN_ASSET_SIZES: &[(usize, u32, u32)] = &[
+    (65536, 256, 256), // full screen dump
+    (64000, 320, 200), // classic VGA mode 13h screen
+    (16384, 128, 128),
+    (4096, 64, 64),
+];
+
+/// Infer (width, height) when neither --width nor --height was given:
+/// check known asset sizes first, then fall back to a square root.
+fn infer_dimensions(byte_len: usize, bpp: u32) -> (u32, u32) {
+    if bpp == 8 {
+        if let Some(&(_, w, h)) = KNOWN_ASSET_SIZES.iter().find(|&&(size, _, _)| size == byte_len) {
+            return (w, h);
+        }
+    }
+
+    let pixel_count = byte_len * 8 / bpp as usize;
+    let side = (pixel_count as f64).sqrt().round() as u32;
+    assert_eq!(
+        (side as usize) * (side as usize), pixel_count,
+        "Could not infer dimensions from {} bytes at {} bpp; pass --width and --height.", byte_len, bpp
+    );
+    (side, side)
+}
+
+/// Unpack `pixel_count` palette indices out of `bytes` at `bpp` bits per pixel.
+fn unpack_indexes(bytes: &[u8], pixel_count: usize, bpp: u32) -> Vec<IndexPixel> {
+    if bpp == 8 {
+        return bytes.iter().take(pixel_count).map(|&raw_index| IndexPixel { raw_index }).collect();
+    }
+
+    let mut indexes = Vec::with_capacity(pixel_count);
+    let mut bit_pos: usize = 0;
+    for _ in 0..pixel_count {
+        let mut raw_index: u8 = 0;
+        for _ in 0..bpp {
+            let byte = bytes[bit_pos / 8];
+            let bit = (byte >> (7 - (bit_pos % 8))) & 1;
+            raw_index = (raw_index << 1) | bit;
+            bit_pos += 1;
+        }
+        indexes.push(IndexPixel { raw_index });
+    }
+    indexes
+}
+
+/// See `high_palette_fallback`.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum Fallback {
+    Green,
+    Grayscale,
+    Vga,
+}
+
+/// Build the 128-entry fill for indices 128..256 when no --palette-path was given.
+fn high_palette_fallback(fallback: Fallback, used_indexes: &[usize]) -> Vec<PalettePixel> {
+    match fallback {
+        Fallback::Green => const_palette(128, PalettePixel { r: 0, g: 255, b: 0 }),
+        Fallback::Vga => {
+            let vga = default_vga_palette();
+            (0..128).map(|i| vga[i % vga.len()].clone()).collect()
+        },
+        Fallback::Grayscale => grayscale_fallback(used_indexes),
+    }
+}
+
+/// See `high_palette_fallback`. Ramps the used high indices if they look
+/// monotonic (`is_monotonic_ramp`), otherwise leaves them electric green.
+fn grayscale_fallback(used_indexes: &[usize]) -> Vec<PalettePixel> {
+    let mut fill = const_palette(128, PalettePixel { r: 0, g: 255, b: 0 });
+
+    let mut high_usage: Vec<usize> = used_indexes.iter().copied().filter(|&index| index >= 128).collect();
+    high_usage.sort_unstable();
+    high_usage.dedup();
+
+    if is_monotonic_ramp(&high_usage) {
+        for index in high_usage {
+            let slot = index - 128;
+            // 6-bit, not 8-bit: this fill goes through the same to_8bit()
+            // pass as every other source below, same as a real .PLT file.
+            let level = ((slot as f64 / 127.0) * 63.0).round() as u8;
+            fill[slot] = PalettePixel { r: level, g: level, b: level };
+        }
+    }
 
-    for (byte, index) in zip(image_file_bytes.clone(), &mut image_file_indexes){
-        dataview::DataView::from_mut(index).write(0, &byte);
+    fill
+}
+
+/// Dense within their span (few gaps) implies a ramp rather than scattered
+/// hits. `used` must already be sorted and deduplicated.
+fn is_monotonic_ramp(used: &[usize]) -> bool {
+    if used.len() < 2 {
+        return false;
     }
-    (image_file_indexes, image_file_bytes)
+
+    let span = used[used.len() - 1] - used[0] + 1;
+    span <= used.len() * 2
 }
 
 /// Assemble a VGA Palette the way I believe SPACESIM.exe does it
 /// VGA Colors as the background, SPACESIM color palette above that, and the per-image/sprite palette filling the top 128 bits
-fn spacesim_palette(palette_path: Option<&Path>, debug: bool) -> Vec<PalettePixel> {
+/// Still in 6-bit source space; see `spacesim_palette` for the 8-bit version.
+fn assemble_palette(palette_path: Option<&Path>, fallback: Fallback, used_indexes: &[usize]) -> Vec<PalettePixel> {
     // Make every color 0 to start.
     let mut palette = const_palette(256, PalettePixel { r: 0, g: 0, b: 0 });
     // Fill with default VGA colors
@@ -159,22 +388,18 @@ fn spacesim_palette(palette_path: Option<&Path>, debug: bool) -> Vec<PalettePixe
     // Overlay the palette from the space simulator dump
     palette = overlay_palette(palette, simulator_dump_palette(), 32);
 
-    // Add in our custom palette if provided
-    // If not, fill the space with electric green to highlight issues.
-    palette = match palette_path {
-        None => overlay_palette(palette, const_palette(128, PalettePixel { r: 0, g: 255, b: 0 }),128),        
+    // Add in our custom palette if provided.
+    // If not, fill the space using the chosen --fallback strategy.
+    match palette_path {
+        None => overlay_palette(palette, high_palette_fallback(fallback, used_indexes), 128),
         Some(p_path) => overlay_palette(palette, load_palette(&p_path), 128),
-    };
+    }
+}
+
+/// `assemble_palette`, converted to 8-bit and optionally saved for inspection.
+fn spacesim_palette(palette_path: Option<&Path>, debug: bool, fallback: Fallback, used_indexes: &[usize]) -> Vec<PalettePixel> {
+    let mut palette = assemble_palette(palette_path, fallback, used_indexes);
 
-    //DEBUG: Identify a line of 24 colors by setting the value to bright green
-    // let mut offset = 32 ;
-    // let line = 2;
-    // let line_length = 24;
-    // offset = offset + line*line_length;
-    // for i in offset..(offset+line_length){
-    //     palette[i] = PalettePixel{r:0,g:255,b:0};
-    // }
-    
     // Color correct every value except the first 16 compatibility colors to an 8 bit representation
     // So they represent what would be visible on modern hardware.
     palette.to_8bit();
@@ -182,58 +407,381 @@ fn spacesim_palette(palette_path: Option<&Path>, debug: bool) -> Vec<PalettePixe
     if debug {
         save_palette(&palette, "IMAGECONVERT_DEBUG");
     }
-    
+
     return palette;
 }
 
-fn image_to_bitmap(image_path: &Path, palette_path: Option<&Path>, debug: bool){
-    println!("Attempting to open image {} using custom palette {}", 
-        image_path.display(), 
+/// Options for `image_to_bitmap`, bundled to avoid too many arguments.
+struct ImageToBitmapOptions {
+    format: OutputFormat,
+    width: Option<u32>,
+    height: Option<u32>,
+    bpp: u32,
+    fallback: Fallback,
+}
+
+fn image_to_bitmap(image_path: &Path, palette_path: Option<&Path>, debug: bool, options: ImageToBitmapOptions){
+    println!("Attempting to open image {} using custom palette {}",
+        image_path.display(),
         match palette_path {
             None => String::from("<No Custom Palette>"),
             Some(p) => p.display().to_string(),
         }
     );
 
-    let (image_file_indexes, image_file_bytes) = load_image(image_path);
+    let image_file_bytes = load_image(image_path);
+    let bpp = options.bpp;
 
-    let palette = spacesim_palette(palette_path, debug);
-
-    // Hardcoded BPP, W, H for space simulator images.
-    let bpp: u32 = 1; // Bits per pixel
-    let width: u32 = 256; // Width (Pixels per row)
-    let height: u32 = 256; // Height
+    let (width, height) = match (options.width, options.height) {
+        (Some(w), Some(h)) => (w, h),
+        (Some(w), None) => (w, (image_file_bytes.len() * 8 / bpp as usize) as u32 / w),
+        (None, Some(h)) => ((image_file_bytes.len() * 8 / bpp as usize) as u32 / h, h),
+        (None, None) => infer_dimensions(image_file_bytes.len(), bpp),
+    };
 
-    // Assert we have the right number of bytes
+    let pixel_count = (width * height) as usize;
+    let required_bytes = (pixel_count * bpp as usize).div_ceil(8);
     assert_eq!(
-        image_file_bytes.len(), 
-        (bpp*width*height).try_into().unwrap(), 
-        "Must supply a 65536 byte 256x256 SPACESIM .R8 image");
+        image_file_bytes.len(), required_bytes,
+        "Expected {} bytes for a {}x{} image at {} bpp, found {}",
+        required_bytes, width, height, bpp, image_file_bytes.len());
 
-    let mut img = Image::new(width, height);
+    let image_file_indexes = unpack_indexes(&image_file_bytes, pixel_count, bpp);
 
-    for ((x,y), index) in zip(img.coordinates(), image_file_indexes) {
-        let palette_index:usize = index.raw_index.into();
-        let color = &palette[palette_index];
-        let pixel = px!(color.r, color.g, color.b);
-        //let pixel = px!(gamma_correct(color.r), gamma_correct(color.g), gamma_correct(color.b));
-        img.set_pixel(x, y, pixel);
-    }
+    let used_indexes: Vec<usize> = image_file_indexes.iter().map(|index| index.raw_index as usize).collect();
+    let palette = spacesim_palette(palette_path, debug, options.fallback, &used_indexes);
 
     // Write out the image
     let path = env::current_dir().unwrap();
     println!("Output Directory: {}",path.display());
     let file_basename = image_path.file_stem().expect("Could not find Base Filename.");
-    let out_filename = format!("{}_{}.BMP", file_basename.to_str().unwrap(), image_path.extension().unwrap().to_str().unwrap());
+
+    match options.format {
+        OutputFormat::Bmp => {
+            let mut img = Image::new(width, height);
+
+            for ((x,y), index) in zip(img.coordinates(), image_file_indexes) {
+                let palette_index:usize = index.raw_index.into();
+                let color = &palette[palette_index];
+                let pixel = px!(color.r, color.g, color.b);
+                //let pixel = px!(gamma_correct(color.r), gamma_correct(color.g), gamma_correct(color.b));
+                img.set_pixel(x, y, pixel);
+            }
+
+            let out_filename = format!("{}_{}.BMP", file_basename.to_str().unwrap(), image_path.extension().unwrap().to_str().unwrap());
+            let outfile = Path::new(&out_filename);
+
+            println!("Writing out {}", outfile.display());
+            let _ = img.save(outfile);
+        },
+        OutputFormat::Png => {
+            let indexes: Vec<u8> = image_file_indexes.iter().map(|index| index.raw_index).collect();
+
+            let out_filename = format!("{}_{}.PNG", file_basename.to_str().unwrap(), image_path.extension().unwrap().to_str().unwrap());
+            let outfile = Path::new(&out_filename);
+
+            println!("Writing out {}", outfile.display());
+            write_indexed_png(outfile, &indexes, width, height, &palette).expect("Could not write indexed PNG.");
+        },
+    }
+
+    println!("Done!");
+}
+
+/// Write a paletted PNG (color type 3): raw palette indices plus a PLTE
+/// chunk. IDAT is zlib-wrapped but stored uncompressed, so no compression
+/// dependency is needed.
+fn write_indexed_png(path: &Path, indexes: &[u8], width: u32, height: u32, palette: &[PalettePixel]) -> std::io::Result<()> {
+    let mut png: Vec<u8> = Vec::new();
+    png.extend_from_slice(&[0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A]);
+
+    let mut ihdr: Vec<u8> = Vec::new();
+    ihdr.extend_from_slice(&width.to_be_bytes());
+    ihdr.extend_from_slice(&height.to_be_bytes());
+    ihdr.push(8); // bit depth
+    ihdr.push(3); // color type: indexed
+    ihdr.push(0); // compression method
+    ihdr.push(0); // filter method
+    ihdr.push(0); // interlace method
+    write_png_chunk(&mut png, b"IHDR", &ihdr);
+
+    let plte: Vec<u8> = palette.iter().flat_map(|c| [c.r, c.g, c.b]).collect();
+    write_png_chunk(&mut png, b"PLTE", &plte);
+
+    // Every scanline is prefixed with a filter-type byte; 0 (None) keeps the
+    // indices byte-for-byte so no filter math is needed.
+    let row_bytes = width as usize;
+    let mut raw: Vec<u8> = Vec::with_capacity(height as usize * (row_bytes + 1));
+    for row in indexes.chunks_exact(row_bytes) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+
+    let idat = zlib_store(&raw);
+    write_png_chunk(&mut png, b"IDAT", &idat);
+
+    write_png_chunk(&mut png, b"IEND", &[]);
+
+    fs::write(path, &png)
+}
+
+fn write_png_chunk(out: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let mut crc_input = Vec::with_capacity(4 + data.len());
+    crc_input.extend_from_slice(chunk_type);
+    crc_input.extend_from_slice(data);
+    out.extend_from_slice(chunk_type);
+    out.extend_from_slice(data);
+    out.extend_from_slice(&crc32(&crc_input).to_be_bytes());
+}
+
+/// Wrap `data` as a zlib stream made of uncompressed/"stored" deflate blocks
+/// (RFC 1951 BTYPE=00).
+fn zlib_store(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + 16);
+    out.push(0x78); // CMF: deflate, 32k window
+    out.push(0x01); // FLG: no preset dictionary, check bits for CMF/FLG
+
+    const MAX_STORED_BLOCK: usize = 0xFFFF;
+    let mut remaining = data;
+    loop {
+        let (chunk, rest) = remaining.split_at(remaining.len().min(MAX_STORED_BLOCK));
+        let is_final = rest.is_empty();
+        out.push(if is_final { 1 } else { 0 });
+        let len = chunk.len() as u16;
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(chunk);
+
+        remaining = rest;
+        if is_final {
+            break;
+        }
+    }
+
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    const MOD_ADLER: u32 = 65521;
+    let mut a: u32 = 1;
+    let mut b: u32 = 0;
+    for &byte in data {
+        a = (a + byte as u32) % MOD_ADLER;
+        b = (b + a) % MOD_ADLER;
+    }
+    (b << 16) | a
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let table = crc32_table();
+    let mut crc: u32 = 0xFFFFFFFF;
+    for &byte in data {
+        let index = ((crc ^ byte as u32) & 0xFF) as usize;
+        crc = table[index] ^ (crc >> 8);
+    }
+    crc ^ 0xFFFFFFFF
+}
+
+fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    for (n, entry) in table.iter_mut().enumerate() {
+        let mut c = n as u32;
+        for _ in 0..8 {
+            c = if c & 1 != 0 { 0xEDB88320 ^ (c >> 1) } else { c >> 1 };
+        }
+        *entry = c;
+    }
+    table
+}
+
+/// Quantize a 24-bit truecolor image down to a SPACESIM .R8 index file
+/// (one byte per pixel, same width and height as the input), the inverse of
+/// `image_to_bitmap`. Each pixel is matched to the
+/// closest color in the assembled `spacesim_palette()`, skipping any index
+/// in `reserve` (by default the first 16 compatibility colors) so they
+/// can't be picked as a match.
+fn bitmap_to_image(image_path: &Path, palette_path: Option<&Path>, reserve: &str, debug: bool, fallback: Fallback) {
+    println!("Quantizing truecolor image {} down to an .R8 using custom palette {}",
+        image_path.display(),
+        match palette_path {
+            None => String::from("<No Custom Palette>"),
+            Some(p) => p.display().to_string(),
+        }
+    );
+
+    // There's no index image here to scan for high-index usage, so the
+    // grayscale fallback (if selected) has nothing to ramp and behaves
+    // like the green fallback.
+    let palette = spacesim_palette(palette_path, debug, fallback, &[]);
+    let reserved = parse_reserve_range(reserve);
+
+    let img = bmp::open(image_path).expect("Could not open truecolor image.");
+    let width = img.get_width();
+    let height = img.get_height();
+
+    let mut indexes: Vec<u8> = Vec::with_capacity((width * height) as usize);
+    for (x, y) in img.coordinates() {
+        let pixel = img.get_pixel(x, y);
+        indexes.push(nearest_palette_index(&palette, pixel.r, pixel.g, pixel.b, &reserved) as u8);
+    }
+
+    let file_basename = image_path.file_stem().expect("Could not find Base Filename.");
+    let out_filename = format!("{}.R8", file_basename.to_str().unwrap());
     let outfile = Path::new(&out_filename);
 
     println!("Writing out {}", outfile.display());
-    let _ = img.save(outfile);
+    fs::write(outfile, &indexes).expect("Could not write .R8 file.");
     println!("Done!");
 }
 
+/// Find the palette index whose color is closest to (r, g, b), skipping
+/// indices in `reserved`. Distance is a perceptually weighted squared
+/// distance (extra weight on green, approximating the human eye's green
+/// sensitivity) rather than plain Euclidean distance. Ties go to the
+/// lowest index, since we scan in index order and only replace on a
+/// strictly smaller distance.
+fn nearest_palette_index(palette: &[PalettePixel], r: u8, g: u8, b: u8, reserved: &std::ops::Range<usize>) -> usize {
+    let mut best_index = 0usize;
+    let mut best_distance = u32::MAX;
+
+    for (index, color) in palette.iter().enumerate() {
+        if reserved.contains(&index) {
+            continue;
+        }
+
+        let dr = r as i32 - color.r as i32;
+        let dg = g as i32 - color.g as i32;
+        let db = b as i32 - color.b as i32;
+        let distance = (2 * dr * dr + 4 * dg * dg + 3 * db * db) as u32;
+
+        if distance < best_distance {
+            best_distance = distance;
+            best_index = index;
+        }
+    }
+
+    best_index
+}
+
+/// Median-cut an image down to a custom 128 color .PLT for the
+/// per-image/sprite region (indices 128..256).
+fn image_to_palette(image_path: &Path) {
+    println!("Building a custom 128 color palette from {}", image_path.display());
+
+    let img = bmp::open(image_path).expect("Could not open truecolor image.");
+    let mut pixels: Vec<PalettePixel> = Vec::with_capacity((img.get_width() * img.get_height()) as usize);
+    for (x, y) in img.coordinates() {
+        let pixel = img.get_pixel(x, y);
+        pixels.push(PalettePixel { r: pixel.r, g: pixel.g, b: pixel.b });
+    }
+
+    let mut palette = median_cut(pixels, 128);
+    for color in &mut palette {
+        color.to_6bit();
+    }
+
+    let bytes: Vec<u8> = palette.iter().flat_map(|c| [c.r, c.g, c.b]).collect();
+
+    let file_basename = image_path.file_stem().expect("Could not find Base Filename.");
+    let out_filename = format!("{}.PLT", file_basename.to_str().unwrap());
+    let outfile = Path::new(&out_filename);
+
+    println!("Writing out {}", outfile.display());
+    fs::write(outfile, &bytes).expect("Could not write .PLT file.");
+    println!("Done!");
+}
+
+/// Gamma for gamma-adjusted channel averaging, so bright outliers don't skew
+/// a bucket's representative color.
+const MEDIAN_CUT_GAMMA: f64 = 0.57;
+
+/// Per-channel weights for bucket range/axis selection; green weighted
+/// highest to match eye sensitivity.
+const MEDIAN_CUT_WEIGHTS: (f64, f64, f64) = (0.5, 1.0, 0.45);
+
+/// Median-cut quantization: repeatedly split the bucket with the largest
+/// weighted range along its longest axis, then average each bucket down to
+/// a representative color.
+fn median_cut(pixels: Vec<PalettePixel>, target_colors: usize) -> Vec<PalettePixel> {
+    let mut buckets: Vec<Vec<PalettePixel>> = vec![pixels];
+
+    while buckets.len() < target_colors {
+        // Only buckets with more than one pixel can still be split.
+        let widest = buckets.iter()
+            .enumerate()
+            .filter(|(_, bucket)| bucket.len() >= 2)
+            .map(|(i, bucket)| (i, widest_axis(bucket)))
+            .max_by(|a, b| (a.1).1.partial_cmp(&(b.1).1).unwrap());
+
+        let (bucket_index, (axis, _range)) = match widest {
+            Some(w) => w,
+            None => break,
+        };
+
+        let bucket = buckets.swap_remove(bucket_index);
+        let mut sorted = bucket;
+        sorted.sort_by(|a, b| axis_value(a, axis).partial_cmp(&axis_value(b, axis)).unwrap());
+        let mid = sorted.len() / 2;
+        let right = sorted.split_off(mid);
+        buckets.push(sorted);
+        buckets.push(right);
+    }
+
+    let mut palette: Vec<PalettePixel> = buckets.iter().map(|bucket| average_bucket(bucket)).collect();
+
+    // Pad out with the last color if there were fewer pixels than target_colors.
+    while palette.len() < target_colors {
+        let last = palette.last().cloned().unwrap_or(PalettePixel { r: 0, g: 0, b: 0 });
+        palette.push(last);
+    }
+
+    palette
+}
+
+/// Gamma-adjusted, weighted value of a pixel along axis 0=r, 1=g, 2=b.
+fn axis_value(pixel: &PalettePixel, axis: usize) -> f64 {
+    let (wr, wg, wb) = MEDIAN_CUT_WEIGHTS;
+    match axis {
+        0 => wr * (pixel.r as f64 / 255.0).powf(MEDIAN_CUT_GAMMA),
+        1 => wg * (pixel.g as f64 / 255.0).powf(MEDIAN_CUT_GAMMA),
+        _ => wb * (pixel.b as f64 / 255.0).powf(MEDIAN_CUT_GAMMA),
+    }
+}
+
+/// The axis with the largest weighted gamma-adjusted range across `bucket`, and its size.
+fn widest_axis(bucket: &[PalettePixel]) -> (usize, f64) {
+    (0..3)
+        .map(|axis| {
+            let values = bucket.iter().map(|p| axis_value(p, axis));
+            let min = values.clone().fold(f64::INFINITY, f64::min);
+            let max = values.fold(f64::NEG_INFINITY, f64::max);
+            (axis, max - min)
+        })
+        .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+        .unwrap()
+}
+
+/// Average a bucket in gamma-adjusted space, then invert back to 8-bit RGB.
+fn average_bucket(bucket: &[PalettePixel]) -> PalettePixel {
+    let gamma_encode = |v: u8| (v as f64 / 255.0).powf(MEDIAN_CUT_GAMMA);
+    let gamma_decode = |v: f64| (v.powf(1.0 / MEDIAN_CUT_GAMMA) * 255.0).round().clamp(0.0, 255.0) as u8;
+
+    let count = bucket.len() as f64;
+    let (sum_r, sum_g, sum_b) = bucket.iter().fold((0.0, 0.0, 0.0), |(sr, sg, sb), p| {
+        (sr + gamma_encode(p.r), sg + gamma_encode(p.g), sb + gamma_encode(p.b))
+    });
+
+    PalettePixel {
+        r: gamma_decode(sum_r / count),
+        g: gamma_decode(sum_g / count),
+        b: gamma_decode(sum_b / count),
+    }
+}
+
 fn palette_file_to_bitmap(palette_path: &Path) {
-    let mut palette = spacesim_palette(Some(palette_path), false);
+    let mut palette = spacesim_palette(Some(palette_path), false, Fallback::Green, &[]);
 
     let path = env::current_dir().unwrap();
     println!("Output Directory: {}",path.display());